@@ -0,0 +1,176 @@
+use crate::{api_url, tz_offset};
+use chrono::FixedOffset;
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// A snapshot of the main loop's sync state, refreshed every tick so
+/// `/stats` can answer without touching the loop itself.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    pub rtt: Duration,
+    pub avg: Duration,
+    pub boundary_phase: f64,
+}
+
+/// State shared between the main loop and the control channel. The loop
+/// reads `tz_override`/`paused`/`template_override` each tick, falling back
+/// to each target's configured tz/template when unset, and writes `stats`;
+/// the control thread does the opposite.
+#[derive(Debug, Default)]
+pub struct State {
+    pub tz_override: Option<FixedOffset>,
+    pub template_override: Option<String>,
+    pub paused: bool,
+    pub stats: Stats,
+}
+
+pub type Shared = Arc<Mutex<State>>;
+
+impl State {
+    pub fn shared() -> Shared {
+        Arc::new(Mutex::new(Self::default()))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Update {
+    update_id: u64,
+    message: Option<IncomingMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IncomingMessage {
+    chat: ChatRef,
+    from: Option<Sender>,
+    text: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatRef {
+    id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Sender {
+    id: i64,
+}
+
+fn get_updates(http: &ureq::Agent, token: &str, offset: u64) -> crate::Result<Vec<Update>> {
+    let mut r = http
+        .get(api_url(token, "getUpdates"))
+        .query("offset", offset.to_string())
+        .query("timeout", "30")
+        .call()?;
+    let r: crate::Response<Vec<Update>> = r.body_mut().read_json()?;
+    if !r.ok {
+        return Err("getUpdates: not ok".into());
+    }
+    Ok(r.result.unwrap_or_default())
+}
+
+fn send_message(http: &ureq::Agent, token: &str, chat_id: &str, text: &str) {
+    let r = http
+        .get(api_url(token, "sendMessage"))
+        .query("chat_id", chat_id)
+        .query("text", text)
+        .call();
+    if let Err(e) = r {
+        error!("control: sendMessage failed: {e}");
+    }
+}
+
+/// Dispatches a single admin command against the shared loop state,
+/// replying in the chat where it makes sense to confirm what happened.
+fn dispatch(http: &ureq::Agent, token: &str, chat_id: &str, state: &Shared, text: &str) {
+    let mut split = text.trim().splitn(2, ' ');
+    let cmd = match split.next() {
+        Some(cmd) if !cmd.is_empty() => cmd,
+        _ => return,
+    };
+    let rest = split.next().unwrap_or("").trim();
+    let reply = match cmd {
+        "/tz" if rest == "reset" => {
+            state.lock().unwrap().tz_override = None;
+            "Timezone override cleared".to_owned()
+        }
+        "/tz" => match rest.parse::<i32>() {
+            Ok(hours) if (-23..=23).contains(&hours) => {
+                state.lock().unwrap().tz_override = Some(tz_offset(hours));
+                format!("Timezone override set to {hours:+}")
+            }
+            Ok(_) => "Timezone must be between -23 and 23".to_owned(),
+            Err(_) => "Usage: /tz <hours>|reset".to_owned(),
+        },
+        "/template" if rest == "reset" => {
+            state.lock().unwrap().template_override = None;
+            "Template override cleared".to_owned()
+        }
+        "/template" if !rest.is_empty() => match crate::validate_template(rest) {
+            Ok(()) => {
+                state.lock().unwrap().template_override = Some(rest.to_owned());
+                format!("Template override set to {rest:?}")
+            }
+            Err(e) => format!("Rejected: {e}"),
+        },
+        "/template" => "Usage: /template <strftime format>|reset".to_owned(),
+        "/pause" => {
+            state.lock().unwrap().paused = true;
+            "Paused".to_owned()
+        }
+        "/resume" => {
+            state.lock().unwrap().paused = false;
+            "Resumed".to_owned()
+        }
+        "/stats" => {
+            let stats = state.lock().unwrap().stats;
+            format!(
+                "rtt={:.3?} avg={:.3?} boundary.phase={:.3}",
+                stats.rtt, stats.avg, stats.boundary_phase
+            )
+        }
+        _ => return,
+    };
+    send_message(http, token, chat_id, &reply);
+}
+
+/// Long-polls `getUpdates` on its own agent and dispatches admin commands
+/// sent in `chat_id` by `admin_id`, mutating `state` without restarting the
+/// process. Messages from other senders in that chat are logged and
+/// ignored; messages from other chats are ignored without logging.
+pub fn spawn(token: String, chat_id: String, admin_id: i64, state: Shared) {
+    thread::spawn(move || {
+        let http = ureq::Agent::new_with_config(ureq::Agent::config_builder().build());
+        let mut offset = 0u64;
+        loop {
+            let updates = match get_updates(&http, &token, offset) {
+                Ok(updates) => updates,
+                Err(e) => {
+                    warn!("control: getUpdates failed: {e}");
+                    thread::sleep(Duration::from_secs(5));
+                    continue;
+                }
+            };
+            for update in updates {
+                offset = update.update_id + 1;
+                let Some(message) = update.message else {
+                    continue;
+                };
+                if message.chat.id.to_string() != chat_id {
+                    continue;
+                }
+                let sender_id = message.from.as_ref().map(|f| f.id);
+                if sender_id != Some(admin_id) {
+                    warn!("control: ignoring command from unauthorized sender {sender_id:?}");
+                    continue;
+                }
+                if let Some(text) = &message.text {
+                    info!("control: {text:?} from {admin_id}");
+                    dispatch(&http, &token, &chat_id, &state, text);
+                }
+            }
+        }
+    });
+}
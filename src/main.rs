@@ -1,4 +1,5 @@
-use chrono::{DateTime, Datelike, FixedOffset, Local, TimeDelta, TimeZone, Timelike, Utc};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, FixedOffset, Local, TimeDelta, TimeZone, Timelike, Utc};
 use log::{debug, error, info, warn};
 use serde::{Deserialize, de::DeserializeOwned};
 use std::env;
@@ -6,6 +7,13 @@ use std::thread::sleep;
 use std::time::{Duration, Instant};
 use ureq::{Agent, RequestBuilder, typestate::WithoutBody};
 
+mod control;
+mod metrics;
+mod tui;
+
+use control::Shared;
+use metrics::Metrics;
+
 #[derive(Debug, Deserialize)]
 struct Response<T> {
     ok: bool,
@@ -33,8 +41,28 @@ struct EditedMessage {
     edit_date: u64,
 }
 
+#[derive(Debug, Deserialize)]
+struct ErrorParameters {
+    retry_after: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorBody {
+    parameters: Option<ErrorParameters>,
+}
+
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
+/// One clock to drive: a chat/pinned message, its timezone, and the
+/// template used to render it, fanned out to once per tick.
+#[derive(Debug)]
+struct Target {
+    chat_id: String,
+    message_id: String,
+    tz: FixedOffset,
+    template: String,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 enum ServerTime {
     Early,
@@ -80,6 +108,12 @@ struct Client {
     http: Agent,
     urls: Vec<String>,
     url_idx: u32,
+    /// Index of the token used to build the request currently in flight,
+    /// so `finalize` knows which slot in `ready_at` to cool down on a 429.
+    last_url_idx: u32,
+    /// Per-token flood-wait deadlines, indexed like `urls`; a token is
+    /// skipped by `edit_message_builder` while still in the future.
+    ready_at: Vec<Instant>,
     time0: Instant,
     time1: Instant,
     date: DateTime<Utc>,
@@ -101,10 +135,13 @@ impl Client {
             .collect::<Vec<_>>();
         urls.shrink_to_fit();
         let time = Instant::now();
+        let ready_at = vec![time; urls.len()];
         Self {
             http: Agent::new_with_config(config),
             urls,
             url_idx: 0,
+            last_url_idx: 0,
+            ready_at,
             time0: time,
             time1: time,
             date: Utc::now(),
@@ -129,6 +166,28 @@ impl Client {
         if code.is_success() {
             self.time0 = Instant::now();
             self.date = Utc::now();
+        } else if code.as_u16() == 429 {
+            let retry_after = r
+                .headers()
+                .get("Retry-After")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok())
+                .or_else(|| {
+                    r.body_mut()
+                        .read_to_string()
+                        .ok()
+                        .and_then(|body| serde_json::from_str::<ErrorBody>(&body).ok())
+                        .and_then(|b| b.parameters)
+                        .and_then(|p| p.retry_after)
+                })
+                .unwrap_or(1);
+            warn!(
+                "response 429 on token #{}: retry_after={retry_after}s",
+                self.last_url_idx
+            );
+            self.ready_at[self.last_url_idx as usize] =
+                Instant::now() + Duration::from_secs(retry_after);
+            return Err(format!("rate limited, retry_after={retry_after}s").into());
         } else {
             error!("response {code}: {r:#?}");
             error!("body: {}", r.body_mut().read_to_string()?);
@@ -170,11 +229,23 @@ impl Client {
         message_id: &str,
         text: &str,
     ) -> RequestBuilder<WithoutBody> {
-        let url = &self.urls[self.url_idx as usize];
-        self.url_idx += 1;
-        if self.url_idx >= self.urls.len() as u32 {
-            self.url_idx = 0;
+        let n = self.urls.len() as u32;
+        let now = Instant::now();
+        let mut chosen = None;
+        for i in 0..n {
+            let candidate = (self.url_idx + i) % n;
+            if self.ready_at[candidate as usize] <= now {
+                chosen = Some(candidate);
+                break;
+            }
         }
+        // All tokens are in flood-wait: fall back to the one we'd have
+        // picked anyway rather than stalling the tick entirely.
+        let chosen = chosen.unwrap_or(self.url_idx);
+        self.last_url_idx = chosen;
+        self.url_idx = (chosen + 1) % n;
+
+        let url = &self.urls[chosen as usize];
         self.http
             .get(url)
             .query("chat_id", chat_id)
@@ -221,47 +292,123 @@ impl Window {
     }
 }
 
-fn format_msg(dt: &DateTime<FixedOffset>) -> String {
-    format!(
-        "怎么都 {}/{}/{} {}:{:02}:{:02} 了",
-        dt.year(),
-        dt.month(),
-        dt.day(),
-        dt.hour(),
-        dt.minute(),
-        dt.second(),
-    )
+/// Default template for a target that doesn't declare its own `M<template>`
+/// line, preserving the original hard-coded message.
+const DEFAULT_TEMPLATE: &str = "怎么都 %-Y/%-m/%-d %-H:%M:%S 了";
+
+/// Escapes MarkdownV2 special characters, per the Bot API's formatting spec.
+fn escape_markdown_v2(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "_*[]()~`>#+-=|{}.!".contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Renders a user-supplied chrono `strftime` template (placeholders plus
+/// literal text) against `dt`, then escapes the result for MarkdownV2.
+fn render_template(template: &str, dt: &DateTime<FixedOffset>) -> String {
+    escape_markdown_v2(&dt.format(template).to_string())
+}
+
+/// Parses `template` as a strftime spec without formatting anything, to
+/// catch an invalid specifier (chrono only notices on `Display`, where it
+/// panics) before it can reach the main loop and take the whole process
+/// down mid-tick. `StrftimeItems` yields `Item::Error` for bad specifiers
+/// instead of panicking, so this needs no `catch_unwind`/panic-hook games.
+fn validate_template(template: &str) -> Result<()> {
+    if StrftimeItems::new(template).any(|item| matches!(item, Item::Error)) {
+        return Err(format!("invalid strftime template {template:?}").into());
+    }
+    Ok(())
 }
 
 fn align_date(date: DateTime<Utc>) -> DateTime<Utc> {
     date - TimeDelta::nanoseconds(date.timestamp_subsec_nanos().into())
 }
 
-#[derive(Debug, Clone, Copy)]
-struct Ratio {
-    v: f64,
-    i: f64,
+/// Turns a signed hour offset (as used by the `T<tz>` config token and the
+/// `/tz` control command) into a `FixedOffset`.
+fn tz_offset(tz: i32) -> FixedOffset {
+    if tz >= 0 {
+        FixedOffset::east_opt(tz * 3600).unwrap()
+    } else {
+        FixedOffset::west_opt((-tz) * 3600).unwrap()
+    }
 }
 
-impl Ratio {
+/// Phase-locks the send-ahead offset to the server's integer-second
+/// boundary by bisection, instead of nudging a PID ratio by coarse +-1
+/// steps.
+///
+/// Each edit is sent `phase` seconds ahead of the target second. An edit
+/// counts as `flipped` when both its `edit_date` and the 1s-resolution
+/// `Date` header already show the target second, and as not flipped when
+/// both still show the previous one; that tells us which side of the true
+/// boundary `phase` fell on. The `Date` second is taken as is: no
+/// half-round-trip (Cristian) correction is applied to it. `lo` tracks the
+/// largest `phase` seen to still land after the boundary (good), `hi` the
+/// smallest seen to land before it (too early); the boundary sits between
+/// them, so their midpoint converges on it geometrically. Until both
+/// bounds are known, `estimate` actively probes past the known one instead
+/// of repeating it, so the missing bound gets established.
+#[derive(Debug, Clone, Copy, Default)]
+struct Boundary {
+    lo: Option<f64>,
+    hi: Option<f64>,
+}
+
+impl Boundary {
     const INITIAL: f64 = 0.5;
-    const K_P: f64 = 0.01;
-    const K_I: f64 = 0.01;
+
+    /// Fixed step taken into the unexplored direction while only one bound
+    /// is known, so the other side of the boundary actually gets probed
+    /// instead of latching onto whichever side the first samples landed on.
+    const PROBE_STEP: f64 = 0.1;
 
     fn new() -> Self {
-        Self {
-            v: Self::INITIAL,
-            i: 0.0,
+        Self::default()
+    }
+
+    /// Folds in one observation: `phase` is the send-ahead offset used for
+    /// this edit, `flipped` is whether the server had already reached the
+    /// target second by the time it replied.
+    fn observe(&mut self, phase: f64, flipped: bool) {
+        if flipped {
+            self.lo = Some(self.lo.map_or(phase, |lo: f64| lo.max(phase)));
+            if self.hi.is_some_and(|hi| phase >= hi) {
+                // A later boundary crossing fell above our upper bound:
+                // `hi` was stale or an outlier, so drop it and re-bisect.
+                self.hi = None;
+            }
+        } else {
+            self.hi = Some(self.hi.map_or(phase, |hi: f64| hi.min(phase)));
+            if self.lo.is_some_and(|lo| phase <= lo) {
+                self.lo = None;
+            }
         }
     }
 
-    fn update(&mut self, error: f64) {
-        self.i += error;
-        self.v = (self.v + Self::K_P * error + Self::K_I * self.i).clamp(0.0, 1.0);
+    /// The current best estimate of the send-ahead offset that lands right
+    /// after the server's second boundary. While only one bound is known,
+    /// steps `PROBE_STEP` past it into the unexplored direction instead of
+    /// returning it verbatim, so the missing bound actually gets found.
+    fn estimate(&self) -> f64 {
+        match (self.lo, self.hi) {
+            (Some(lo), Some(hi)) => (lo + hi) / 2.0,
+            (Some(lo), None) => lo + Self::PROBE_STEP,
+            (None, Some(hi)) => hi - Self::PROBE_STEP,
+            (None, None) => Self::INITIAL,
+        }
     }
 
-    fn apply(&mut self, dur: Duration) -> Duration {
-        dur.mul_f64(self.v)
+    /// Width of the bracket around the estimate, as a convergence signal;
+    /// `None` until both bounds have been observed at least once.
+    fn gap(&self) -> Option<f64> {
+        Some(self.hi? - self.lo?)
     }
 }
 
@@ -269,100 +416,218 @@ fn main() -> Result<()> {
     pretty_env_logger::init_timed();
 
     let file = env::args().nth(1).unwrap();
+    let tui_mode = env::args().any(|a| a == "--tui");
     let config = std::fs::read_to_string(file).unwrap();
 
-    let mut chat_id = "";
-    let mut tz = i32::MAX;
-    let mut tokens = config
-        .split_whitespace()
-        .filter(|s| match s.chars().next() {
+    struct PendingTarget {
+        chat_id: String,
+        tz: Option<i32>,
+        template: Option<String>,
+    }
+
+    let mut metrics_port: Option<u16> = None;
+    let mut admin_id: Option<i64> = None;
+    let mut pending: Option<PendingTarget> = None;
+    let mut target_specs = Vec::new();
+    let mut bot_tokens = Vec::new();
+
+    for line in config.lines().map(str::trim).filter(|s| !s.is_empty()) {
+        match line.chars().next() {
             Some('#') => {
-                chat_id = &s[1..];
-                false
+                if let Some(p) = pending.take() {
+                    target_specs.push(p);
+                }
+                pending = Some(PendingTarget {
+                    chat_id: line[1..].to_owned(),
+                    tz: None,
+                    template: None,
+                });
             }
             Some('T') => {
-                tz = s[1..].parse().unwrap();
-                false
+                pending.as_mut().expect("T<tz> without a preceding #chat_id").tz =
+                    Some(line[1..].parse().unwrap());
+            }
+            Some('M') => {
+                pending
+                    .as_mut()
+                    .expect("M<template> without a preceding #chat_id")
+                    .template = Some(line[1..].to_owned());
             }
-            _ => s.contains(':'),
-        })
-        .peekable();
+            Some('P') => {
+                metrics_port = Some(line[1..].parse().unwrap());
+            }
+            Some('A') => {
+                admin_id = Some(line[1..].parse().unwrap());
+            }
+            _ if line.contains(':') => bot_tokens.push(line),
+            _ => {}
+        }
+    }
+    if let Some(p) = pending.take() {
+        target_specs.push(p);
+    }
 
-    let token = *tokens.peek().unwrap();
-    let mut cli = Client::new(tokens);
+    assert!(!target_specs.is_empty(), "No chat ID (#...)");
+    assert!(!bot_tokens.is_empty(), "No bot token");
+    let admin_id = admin_id.expect("No admin user ID (A...) for the control channel");
+
+    let token = bot_tokens[0].to_owned();
+    let mut cli = Client::new(bot_tokens.into_iter());
+
+    let local_tz = Local.offset_from_utc_datetime(&DateTime::UNIX_EPOCH.naive_utc());
+    let mut targets = Vec::with_capacity(target_specs.len());
+    for spec in target_specs {
+        let tz = spec.tz.map(tz_offset).unwrap_or(local_tz);
+        let template = spec.template.unwrap_or_else(|| DEFAULT_TEMPLATE.to_owned());
+        validate_template(&template)
+            .map_err(|e| format!("chat {}: {e}", spec.chat_id))?;
+        let chat = cli.get_chat(&token, &spec.chat_id)?;
+        info!("Chat {}: {chat:#?} (tz={tz} template={template:?})", spec.chat_id);
+        let message_id = chat.unwrap().to_string();
+        targets.push(Target {
+            chat_id: spec.chat_id,
+            message_id,
+            tz,
+            template,
+        });
+    }
+    drop(config);
 
-    assert!(!chat_id.is_empty(), "No chat ID (#...)");
-    let tz = if tz == i32::MAX {
-        Local.offset_from_utc_datetime(&DateTime::UNIX_EPOCH.naive_utc())
-    } else if tz >= 0 {
-        FixedOffset::east_opt(tz * 3600).unwrap()
-    } else {
-        FixedOffset::west_opt((-tz) * 3600).unwrap()
-    };
-    info!("Timezone: {tz}");
+    let metrics = Metrics::new();
+    if let Some(port) = metrics_port {
+        metrics.serve(port);
+    }
 
-    let chat_id = chat_id.to_owned();
-    let chat = cli.get_chat(token, &chat_id)?;
-    drop(config);
-    info!("Chat: {chat:#?}");
-    let message_id = chat.unwrap().to_string();
+    let state: Shared = control::State::shared();
+    control::spawn(token, targets[0].chat_id.clone(), admin_id, state.clone());
+
+    let samples = tui::new_samples();
+    if tui_mode {
+        let samples = samples.clone();
+        std::thread::spawn(move || {
+            // Quitting the dashboard (or a transient crossterm error) just
+            // ends this thread; the clock-edit loop, metrics server and
+            // control channel all keep running headless.
+            if let Err(e) = tui::run(samples) {
+                error!("tui: {e:#?}");
+            }
+        });
+    }
+
+    // Samples whose RTT exceeds this many times the running average are
+    // jittered outliers and aren't fed into the boundary estimate.
+    const RTT_OUTLIER_FACTOR: f64 = 3.0;
 
     let mut win = Window::new();
     let mut avg = Duration::default();
-    let mut ratio = Ratio::new();
+    let mut boundary = Boundary::new();
 
     let mut date = align_date(Utc::now());
     cli.set_second(date.second());
 
-    let mut msg = format_msg(&date.with_timezone(&tz));
-    let mut req = cli.edit_message_builder(&chat_id, &message_id, &msg);
+    // The send-ahead offset decided for this tick; each target's actual
+    // phase (fed to `boundary.observe`) is derived from this minus however
+    // long the tick's earlier targets took, since they're edited in series.
+    let mut off = Duration::from_secs_f64(Boundary::INITIAL);
 
     loop {
         const DELAY: TimeDelta = TimeDelta::seconds(1);
 
-        let t0 = Instant::now();
-        let resp = cli.edit_message(req);
-        let now = Utc::now();
-        let off = if let Err(e) = resp {
-            error!("edit failed: {e:#?}");
-            ratio.apply(avg)
-        } else {
+        if state.lock().unwrap().paused {
+            sleep(Duration::from_secs(1));
+            continue;
+        }
+
+        let tick_start = Instant::now();
+        for target in &targets {
+            let (tz_override, template_override) = {
+                let st = state.lock().unwrap();
+                (st.tz_override, st.template_override.clone())
+            };
+            let tz = tz_override.unwrap_or(target.tz);
+            let template = template_override.as_deref().unwrap_or(&target.template);
+            let msg = render_template(template, &date.with_timezone(&tz));
+            let req = cli.edit_message_builder(&target.chat_id, &target.message_id, &msg);
+
+            let t0 = Instant::now();
+            // `off` was the send-ahead offset decided before this tick's
+            // loop started; targets after the first depart later than that
+            // by however long the earlier targets' round trips took, so
+            // subtract the elapsed time to get this edit's actual phase.
+            let phase = off.saturating_sub(t0.duration_since(tick_start));
+            let resp = cli.edit_message(req);
+            if let Err(e) = resp {
+                error!("edit failed for {}: {e:#?}", target.chat_id);
+                continue;
+            }
             debug!("msg: {msg}");
 
-            if !win.is_empty() {
+            metrics.observe_server_time(cli.server_time_field, cli.server_time_header);
+
+            let rtt = cli.time0 - t0;
+            let (sign, diff) = if avg >= rtt {
+                ('+', avg - rtt)
+            } else {
+                ('-', rtt - avg)
+            };
+
+            if !win.is_empty() && rtt <= avg.mul_f64(RTT_OUTLIER_FACTOR) {
                 use ServerTime::{Early, Late};
                 match (cli.server_time_field, cli.server_time_header) {
-                    (Early, Early) => ratio.update(-1.0),
+                    (Early, Early) => boundary.observe(phase.as_secs_f64(), false),
                     (Early, Late) => {}
-                    (Late, Late) => ratio.update(1.0),
+                    (Late, Late) => boundary.observe(phase.as_secs_f64(), true),
                     (Late, Early) => error!("Server Date is earlier!"),
                     _ => warn!("Unexpected server time (too slow?)"),
                 }
+            } else if !win.is_empty() {
+                debug!("discarding jittered sample: rtt={rtt:.3?} avg={avg:.3?}");
             }
 
-            let rtt = cli.time0 - t0;
-            let (sign, diff) = if avg >= rtt {
-                ('+', avg - rtt)
-            } else {
-                ('-', rtt - avg)
-            };
             win.push(rtt);
             avg = win.avg();
 
             let t1 = cli.time1 - t0;
-            let t0 = cli.date - date;
-            let (sign0, t0) = match t0.to_std() {
+            let t0d = cli.date - date;
+            let (sign0, t0d) = match t0d.to_std() {
                 Ok(dur) => ('+', dur),
                 Err(_) => ('-', (date - cli.date).to_std().unwrap()),
             };
-            let off = ratio.apply(avg);
+            let estimate = boundary.estimate();
             info!(
-                "rtt={rtt:.3?} avg={avg:.3?} err={sign}{diff:.3?} t0={sign0}{t0:.3?} t1={t1:.3?} off={off:.3?} rr={} r={:.3} i={} S={}{}",
-                cli.url_idx, ratio.v, ratio.i, cli.server_time_field, cli.server_time_header,
+                "chat={} rtt={rtt:.3?} avg={avg:.3?} err={sign}{diff:.3?} t0={sign0}{t0d:.3?} t1={t1:.3?} phase={phase:.3?} est={estimate:.3} gap={:.3?} rr={} S={}{}",
+                target.chat_id, boundary.gap(), cli.url_idx, cli.server_time_field, cli.server_time_header,
+            );
+            metrics.observe_boundary(&target.chat_id, estimate, boundary.gap());
+            let t0_signed = if sign0 == '+' {
+                t0d.as_secs_f64()
+            } else {
+                -t0d.as_secs_f64()
+            };
+            metrics.observe_tick(&target.chat_id, avg, phase, t0_signed, t1);
+            {
+                let mut st = state.lock().unwrap();
+                st.stats = control::Stats {
+                    rtt,
+                    avg,
+                    boundary_phase: estimate,
+                };
+            }
+            tui::push(
+                &samples,
+                tui::Sample {
+                    time: cli.date,
+                    msg: msg.clone(),
+                    rtt,
+                    boundary_phase: estimate,
+                    boundary_gap: boundary.gap().unwrap_or(0.0),
+                    field: cli.server_time_field,
+                    header: cli.server_time_header,
+                },
             );
-            off
-        };
+        }
 
+        let now = Utc::now();
         date += DELAY;
         if date < now {
             warn!("Too slow: {date} < {now}");
@@ -370,11 +635,7 @@ fn main() -> Result<()> {
         }
         cli.set_second(date.second());
 
-        let date_tz = date.with_timezone(&tz);
-        msg = format_msg(&date_tz);
-        req = cli.edit_message_builder(&chat_id, &message_id, &msg);
-        debug!("{msg:?} at {date_tz} - {off:?}");
-
+        off = Duration::from_secs_f64(boundary.estimate().clamp(0.0, 0.999));
         let until = date - off;
         let td = until - Utc::now();
         if let Ok(dur) = td.to_std() {
@@ -0,0 +1,170 @@
+use crate::ServerTime;
+use log::{error, info};
+use prometheus::{Encoder, GaugeVec, IntCounterVec, Opts, Registry, TextEncoder};
+use std::io::Write;
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+impl ServerTime {
+    fn label(self) -> &'static str {
+        match self {
+            ServerTime::Early => "early",
+            ServerTime::Late => "late",
+            ServerTime::Other => "other",
+        }
+    }
+}
+
+/// Exports the controller's internal state as Prometheus metrics, so
+/// convergence and drift can be graphed instead of scraped from the logs.
+/// Per-target gauges are labeled by `chat_id` since a config can fan out to
+/// more than one clock, each converging independently.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    rtt_avg: GaugeVec,
+    boundary_phase: GaugeVec,
+    boundary_gap: GaugeVec,
+    off: GaugeVec,
+    t0: GaugeVec,
+    t1: GaugeVec,
+    server_time_field: IntCounterVec,
+    server_time_header: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let rtt_avg = GaugeVec::new(
+            Opts::new("clock_bot_rtt_avg_seconds", "EWMA round-trip time"),
+            &["chat_id"],
+        )
+        .unwrap();
+        let boundary_phase = GaugeVec::new(
+            Opts::new(
+                "clock_bot_boundary_phase_seconds",
+                "Estimated send-ahead offset of the server's second boundary",
+            ),
+            &["chat_id"],
+        )
+        .unwrap();
+        let boundary_gap = GaugeVec::new(
+            Opts::new(
+                "clock_bot_boundary_gap_seconds",
+                "Width of the bisection bracket around the boundary estimate",
+            ),
+            &["chat_id"],
+        )
+        .unwrap();
+        let off = GaugeVec::new(
+            Opts::new("clock_bot_off_seconds", "Computed send offset"),
+            &["chat_id"],
+        )
+        .unwrap();
+        let t0 = GaugeVec::new(
+            Opts::new("clock_bot_t0_seconds", "Local date minus reported date"),
+            &["chat_id"],
+        )
+        .unwrap();
+        let t1 = GaugeVec::new(
+            Opts::new("clock_bot_t1_seconds", "Request-to-response split"),
+            &["chat_id"],
+        )
+        .unwrap();
+        let server_time_field = IntCounterVec::new(
+            Opts::new(
+                "clock_bot_server_time_field_total",
+                "ServerTime classification of edit_date, by class",
+            ),
+            &["class"],
+        )
+        .unwrap();
+        let server_time_header = IntCounterVec::new(
+            Opts::new(
+                "clock_bot_server_time_header_total",
+                "ServerTime classification of the Date header, by class",
+            ),
+            &["class"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(rtt_avg.clone())).unwrap();
+        registry.register(Box::new(boundary_phase.clone())).unwrap();
+        registry.register(Box::new(boundary_gap.clone())).unwrap();
+        registry.register(Box::new(off.clone())).unwrap();
+        registry.register(Box::new(t0.clone())).unwrap();
+        registry.register(Box::new(t1.clone())).unwrap();
+        registry
+            .register(Box::new(server_time_field.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(server_time_header.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            rtt_avg,
+            boundary_phase,
+            boundary_gap,
+            off,
+            t0,
+            t1,
+            server_time_field,
+            server_time_header,
+        }
+    }
+
+    /// Starts a tiny HTTP server on `port` that serves the registry in the
+    /// Prometheus text exposition format on every request.
+    pub fn serve(&self, port: u16) {
+        let registry = self.registry.clone();
+        thread::spawn(move || {
+            let listener = match TcpListener::bind(("0.0.0.0", port)) {
+                Ok(l) => l,
+                Err(e) => {
+                    error!("metrics: failed to bind :{port}: {e}");
+                    return;
+                }
+            };
+            info!("metrics: listening on :{port}");
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let mut buf = Vec::new();
+                if let Err(e) = TextEncoder::new().encode(&registry.gather(), &mut buf) {
+                    error!("metrics: encode failed: {e}");
+                    continue;
+                }
+                let header = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    buf.len()
+                );
+                let _ = stream.write_all(header.as_bytes());
+                let _ = stream.write_all(&buf);
+            }
+        });
+    }
+
+    pub fn observe_boundary(&self, chat_id: &str, estimate: f64, gap: Option<f64>) {
+        self.boundary_phase.with_label_values(&[chat_id]).set(estimate);
+        if let Some(gap) = gap {
+            self.boundary_gap.with_label_values(&[chat_id]).set(gap);
+        }
+    }
+
+    pub fn observe_tick(&self, chat_id: &str, rtt_avg: Duration, off: Duration, t0: f64, t1: Duration) {
+        self.rtt_avg.with_label_values(&[chat_id]).set(rtt_avg.as_secs_f64());
+        self.off.with_label_values(&[chat_id]).set(off.as_secs_f64());
+        self.t0.with_label_values(&[chat_id]).set(t0);
+        self.t1.with_label_values(&[chat_id]).set(t1.as_secs_f64());
+    }
+
+    pub fn observe_server_time(&self, field: ServerTime, header: ServerTime) {
+        self.server_time_field
+            .with_label_values(&[field.label()])
+            .inc();
+        self.server_time_header
+            .with_label_values(&[header.label()])
+            .inc();
+    }
+}
@@ -0,0 +1,157 @@
+use crate::ServerTime;
+use chrono::{DateTime, Utc};
+use crossterm::event::{self, Event, KeyCode};
+use crossterm::terminal::{EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, List, ListItem, Paragraph, Sparkline};
+use ratatui::{Frame, Terminal};
+use std::collections::VecDeque;
+use std::io::stdout;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const MAX_SAMPLES: usize = 512;
+const TICK: Duration = Duration::from_millis(200);
+
+/// One target's edit in a tick of the main loop, pushed into the shared
+/// ring buffer for the dashboard to render.
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub time: DateTime<Utc>,
+    pub msg: String,
+    pub rtt: Duration,
+    pub boundary_phase: f64,
+    pub boundary_gap: f64,
+    pub field: ServerTime,
+    pub header: ServerTime,
+}
+
+pub type Samples = Arc<Mutex<VecDeque<Sample>>>;
+
+pub fn new_samples() -> Samples {
+    Arc::new(Mutex::new(VecDeque::with_capacity(MAX_SAMPLES)))
+}
+
+pub fn push(samples: &Samples, sample: Sample) {
+    let mut samples = samples.lock().unwrap();
+    if samples.len() == MAX_SAMPLES {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
+
+/// Scroll offset into the edit history, counted back from the most recent
+/// entry, clamped to what's actually buffered.
+struct History {
+    offset: usize,
+}
+
+impl History {
+    fn new() -> Self {
+        Self { offset: 0 }
+    }
+
+    fn scroll(&mut self, delta: isize, len: usize) {
+        let max = len.saturating_sub(1);
+        self.offset = (self.offset as isize + delta).clamp(0, max as isize) as usize;
+    }
+}
+
+/// Runs the live dashboard on the current thread until the user quits. Takes
+/// over the terminal for the duration of the call.
+pub fn run(samples: Samples) -> crate::Result<()> {
+    enable_raw_mode()?;
+    let mut out = stdout();
+    crossterm::execute!(out, EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(out))?;
+
+    let mut history = History::new();
+    let result = (|| -> crate::Result<()> {
+        loop {
+            let snapshot: Vec<Sample> = samples.lock().unwrap().iter().cloned().collect();
+            terminal.draw(|f| draw(f, &snapshot, &history))?;
+
+            if event::poll(TICK)?
+                && let Event::Key(key) = event::read()?
+            {
+                match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                    KeyCode::Up => history.scroll(1, snapshot.len()),
+                    KeyCode::Down => history.scroll(-1, snapshot.len()),
+                    _ => {}
+                }
+            }
+        }
+    })();
+
+    disable_raw_mode()?;
+    crossterm::execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    result
+}
+
+fn draw(f: &mut Frame, samples: &[Sample], history: &History) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(3),
+            Constraint::Length(7),
+            Constraint::Min(3),
+        ])
+        .split(f.area());
+
+    let latest = samples.last();
+    let status = latest
+        .map(|s| {
+            format!(
+                "boundary.phase={:.3} boundary.gap={:.3} S={}{}",
+                s.boundary_phase, s.boundary_gap, s.field, s.header
+            )
+        })
+        .unwrap_or_else(|| "waiting for first edit...".to_owned());
+    f.render_widget(
+        Paragraph::new(status).block(Block::default().borders(Borders::ALL).title("PLL")),
+        chunks[0],
+    );
+
+    let rtts: Vec<u64> = samples.iter().map(|s| s.rtt.as_millis() as u64).collect();
+    f.render_widget(
+        Sparkline::default()
+            .block(Block::default().borders(Borders::ALL).title("RTT (ms)"))
+            .data(&rtts)
+            .style(Style::default().fg(Color::Cyan)),
+        chunks[1],
+    );
+
+    let end = samples.len().saturating_sub(history.offset);
+    let start = end.saturating_sub((chunks[2].height as usize).max(1));
+    let items: Vec<ListItem> = samples[start..end]
+        .iter()
+        .rev()
+        .map(|s| {
+            let color = match (s.field, s.header) {
+                (ServerTime::Early, ServerTime::Early) => Color::Red,
+                (ServerTime::Late, ServerTime::Late) => Color::Green,
+                _ => Color::Gray,
+            };
+            ListItem::new(Line::from(format!(
+                "{} {} S={}{}",
+                s.time.format("%H:%M:%S"),
+                s.msg,
+                s.field,
+                s.header
+            )))
+            .style(Style::default().fg(color))
+        })
+        .collect();
+    f.render_widget(
+        List::new(items).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("History (↑/↓ to scroll, q to quit)"),
+        ),
+        chunks[2],
+    );
+}